@@ -27,6 +27,10 @@ pub enum FileType {
 
 pub trait ToFileType {
     fn to_enum(self) -> FileType;
+    /// As [`to_enum`](ToFileType::to_enum), but also reports whether the
+    /// entry is a special file (fifo, socket or device) so the copier
+    /// can skip or recreate it rather than trying to stream its bytes.
+    fn to_enum_special(self) -> (FileType, bool);
 }
 
 fn to_enum(ft: fs::FileType) -> FileType {
@@ -41,10 +45,19 @@ fn to_enum(ft: fs::FileType) -> FileType {
     }
 }
 
+fn is_special(ft: fs::FileType) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    ft.is_fifo() || ft.is_socket() || ft.is_block_device() || ft.is_char_device()
+}
+
 impl ToFileType for fs::FileType {
     fn to_enum(self) -> FileType {
         to_enum(self)
     }
+
+    fn to_enum_special(self) -> (FileType, bool) {
+        (to_enum(self), is_special(self))
+    }
 }
 
 pub fn empty(path: &Path) -> bool {